@@ -0,0 +1,336 @@
+//! Scheduler-side worker/task registries and the bookkeeping that turns a
+//! `FromWorkerMessage` into a state transition. Wrapped in [`CoreRef`] (a
+//! `WrappedRcRefCell<Core>`) so every worker connection driven by the
+//! single-threaded reactor in [`crate::worker`] can share one `Core`
+//! without locking.
+
+use crate::common::WrappedRcRefCell;
+use crate::messages::workermsg::TaskErredMsg;
+use crate::prelude::*;
+use crate::task::TaskRuntimeState;
+use crate::worker::WorkerRef;
+use std::collections::HashMap;
+
+/// Key a task is addressed by on the wire; kept as a local alias the same
+/// way [`crate::worker::reactor`] keeps its own `TaskKey`, so this module
+/// doesn't have to pull in the scheduler's richer key type.
+type TaskKey = String;
+
+/// Retry budget a task falls back to when it doesn't set its own
+/// `max_retries`. The request-level "configurable per-task retry count"
+/// lives on `Task::max_retries` (task submission, outside this module);
+/// this is only the default it's expected to start from.
+pub const DEFAULT_MAX_TASK_RETRIES: u32 = 3;
+
+pub type CoreRef = WrappedRcRefCell<Core>;
+
+pub struct Core {
+    workers: HashMap<WorkerId, WorkerRef>,
+    next_worker_id: WorkerId,
+
+    /// Every task Core knows about, addressable by its wire key. Populated
+    /// by [`Core::register_task`] from wherever task submission lives (not
+    /// part of this module); looked up here whenever a `FromWorkerMessage`
+    /// only carries a key and needs the matching [`TaskRef`] resolved.
+    tasks: HashMap<TaskKey, TaskRef>,
+
+    /// How many times each task has already been rescheduled after a
+    /// `TaskErred`, keyed by its `TaskRef` identity. Cleared once a task
+    /// finishes or its retries are exhausted.
+    retry_counts: HashMap<TaskRef, u32>,
+
+    /// Candidate peer addresses known to hold each key, used to resolve a
+    /// `ComputeTaskMsg.who_has` entry. Gains an entry (the computing
+    /// worker's `listen_address`) whenever a task finishes, and loses every
+    /// entry pointing at a worker that's since been evicted.
+    who_has: HashMap<TaskKey, Vec<String>>,
+}
+
+impl Default for Core {
+    fn default() -> Self {
+        Core {
+            workers: HashMap::new(),
+            next_worker_id: 0,
+            tasks: HashMap::new(),
+            retry_counts: HashMap::new(),
+            who_has: HashMap::new(),
+        }
+    }
+}
+
+impl Core {
+    pub fn new_worker_id(&mut self) -> WorkerId {
+        let id = self.next_worker_id;
+        self.next_worker_id += 1;
+        id
+    }
+
+    pub fn register_worker(&mut self, worker_ref: WorkerRef) {
+        let id = worker_ref.get().id;
+        self.workers.insert(id, worker_ref);
+    }
+
+    pub fn unregister_worker(&mut self, worker_id: WorkerId) {
+        self.workers.remove(&worker_id);
+    }
+
+    pub fn workers(&self) -> impl Iterator<Item = &WorkerRef> {
+        self.workers.values()
+    }
+
+    /// Makes `task_ref` resolvable by key for later `FromWorkerMessage`
+    /// handling (`on_task_finished`, `on_task_erred`). Called by task
+    /// submission, which lives outside this module.
+    pub fn register_task(&mut self, key: TaskKey, task_ref: TaskRef) {
+        self.tasks.insert(key, task_ref);
+    }
+
+    pub fn on_task_finished(
+        &mut self,
+        worker_ref: &WorkerRef,
+        msg: crate::messages::workermsg::TaskFinishedMsg,
+        _new_ready_scheduled: &mut Vec<TaskRef>,
+    ) {
+        if let Some(task_ref) = self.tasks.get(&msg.key) {
+            self.retry_counts.remove(task_ref);
+        }
+        let holder = worker_ref.get().listen_address.clone();
+        let holders = self.who_has.entry(msg.key).or_insert_with(Vec::new);
+        if !holders.contains(&holder) {
+            holders.push(holder);
+        }
+    }
+
+    /// Returns a stranded task (one never dispatched from a draining worker's
+    /// queue, or one held by a worker that was lost) to the ready pool: clears
+    /// its worker assignment and puts it back in `Waiting` so the scheduler
+    /// picks it up for the next worker with room, instead of it being
+    /// silently dropped along with the connection that was holding it.
+    pub fn reschedule_task(&mut self, task_ref: TaskRef) {
+        let mut task = task_ref.get_mut();
+        task.worker = None;
+        task.state = TaskRuntimeState::Waiting;
+        log::debug!(
+            "Task id={} returned to the ready pool for rescheduling",
+            task.id
+        );
+    }
+
+    /// Picks the least-loaded non-draining worker other than `exclude` to
+    /// retry a failed task on. `None` if `exclude` is the only worker we
+    /// have (or every other one is draining), in which case there is
+    /// nowhere to retry onto yet.
+    fn pick_retry_worker(&self, exclude: WorkerId) -> Option<WorkerRef> {
+        self.workers
+            .values()
+            .filter(|w| {
+                let w = w.get();
+                w.id != exclude && !w.draining
+            })
+            .min_by_key(|w| w.get().outstanding_tasks)
+            .cloned()
+    }
+
+    /// Handles a `TaskErred`: captures the exception, and either reassigns
+    /// the task to a different worker (while its own `max_retries` allows
+    /// it, pushing it onto `new_ready_scheduled` just like a fresh schedule
+    /// would — with `task.worker` already set, so the recv loop's
+    /// `task.worker.clone().unwrap()` has something to unwrap) or moves it
+    /// to `Failed` and marks every dependent `Erred`. The failure is
+    /// recorded only in these task states; nothing is sent to the client
+    /// from here. If retries remain but no other worker is available, the
+    /// task is put back in `Waiting` with no worker assigned.
+    pub fn on_task_erred(
+        &mut self,
+        worker_ref: &WorkerRef,
+        msg: TaskErredMsg,
+        new_ready_scheduled: &mut Vec<TaskRef>,
+    ) {
+        let task_ref = match self.tasks.get(&msg.key) {
+            Some(task_ref) => task_ref.clone(),
+            None => {
+                log::warn!("TaskErred for unknown task {}", msg.key);
+                return;
+            }
+        };
+        task_ref.get_mut().exception = Some(msg.exception.clone());
+
+        let failed_worker_id = worker_ref.get().id;
+        let max_retries = task_ref.get().max_retries;
+        let retries = {
+            let count = self.retry_counts.entry(task_ref.clone()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if retries <= max_retries {
+            match self.pick_retry_worker(failed_worker_id) {
+                Some(next_worker_ref) => {
+                    log::warn!(
+                        "Task {} erred on worker {} (attempt {} of {}), retrying on worker {}",
+                        msg.key,
+                        failed_worker_id,
+                        retries,
+                        max_retries,
+                        next_worker_ref.get().id
+                    );
+                    let mut task = task_ref.get_mut();
+                    task.worker = Some(next_worker_ref);
+                    drop(task);
+                    new_ready_scheduled.push(task_ref);
+                }
+                None => {
+                    log::warn!(
+                        "Task {} erred on worker {} (attempt {} of {}) but no other worker is \
+                         available to retry it on; leaving it unassigned",
+                        msg.key,
+                        failed_worker_id,
+                        retries,
+                        max_retries
+                    );
+                    let mut task = task_ref.get_mut();
+                    task.worker = None;
+                    task.state = TaskRuntimeState::Waiting;
+                }
+            }
+        } else {
+            log::error!(
+                "Task {} erred on worker {} and exhausted its {} retries, giving up",
+                msg.key,
+                failed_worker_id,
+                max_retries
+            );
+            task_ref.get_mut().state = TaskRuntimeState::Failed;
+            self.mark_dependents_erred(&task_ref);
+            self.retry_counts.remove(&task_ref);
+        }
+    }
+
+    /// Recursively marks `task_ref`'s dependents `Erred`, since none of them
+    /// can ever produce a result once one of their dependencies has
+    /// permanently `Failed`.
+    fn mark_dependents_erred(&mut self, task_ref: &TaskRef) {
+        let dependents = task_ref.get().dependents.clone();
+        for dependent in dependents {
+            dependent.get_mut().state = TaskRuntimeState::Erred;
+            self.mark_dependents_erred(&dependent);
+        }
+    }
+
+    /// Evicts a worker that [`crate::worker::monitor_worker_heartbeats`] (or
+    /// an ungraceful connection close) has given up on: drops it from the
+    /// registry, purges it from `who_has` so nothing keeps trying to fetch
+    /// dependencies from it, and reschedules every task that was
+    /// `Assigned`/`Computing` on it, the same way a stranded task is handed
+    /// back to `Core` on a graceful drain.
+    pub fn on_worker_lost(&mut self, worker_id: WorkerId) {
+        let listen_address = self
+            .workers
+            .remove(&worker_id)
+            .map(|w| w.get().listen_address.clone());
+        if let Some(listen_address) = listen_address {
+            self.who_has.retain(|_key, holders| {
+                holders.retain(|addr| *addr != listen_address);
+                !holders.is_empty()
+            });
+        }
+
+        let stranded: Vec<TaskRef> = self
+            .tasks
+            .values()
+            .filter(|task_ref| {
+                let task = task_ref.get();
+                matches!(
+                    task.state,
+                    TaskRuntimeState::Assigned | TaskRuntimeState::Computing
+                ) && task
+                    .worker
+                    .as_ref()
+                    .map(|w| w.get().id == worker_id)
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+
+        if !stranded.is_empty() {
+            log::warn!(
+                "Worker {} lost with {} task(s) in flight, rescheduling them",
+                worker_id,
+                stranded.len()
+            );
+        }
+        for task_ref in stranded {
+            self.reschedule_task(task_ref);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::test_worker;
+
+    #[test]
+    fn new_worker_id_hands_out_increasing_ids() {
+        let mut core = Core::default();
+        assert_eq!(core.new_worker_id(), 0);
+        assert_eq!(core.new_worker_id(), 1);
+        assert_eq!(core.new_worker_id(), 2);
+    }
+
+    #[test]
+    fn register_and_unregister_worker_round_trip() {
+        let mut core = Core::default();
+        let worker_ref = WorkerRef::wrap(test_worker(0, 0, false));
+        core.register_worker(worker_ref);
+        assert_eq!(core.workers().count(), 1);
+
+        core.unregister_worker(0);
+        assert_eq!(core.workers().count(), 0);
+    }
+
+    #[test]
+    fn pick_retry_worker_excludes_the_failed_worker() {
+        let mut core = Core::default();
+        core.register_worker(WorkerRef::wrap(test_worker(0, 0, false)));
+        let picked = core.pick_retry_worker(0);
+        assert!(picked.is_none());
+    }
+
+    #[test]
+    fn pick_retry_worker_prefers_the_least_loaded_live_worker() {
+        let mut core = Core::default();
+        core.register_worker(WorkerRef::wrap(test_worker(0, 5, false)));
+        core.register_worker(WorkerRef::wrap(test_worker(1, 1, false)));
+        core.register_worker(WorkerRef::wrap(test_worker(2, 0, true))); // draining
+
+        let picked = core.pick_retry_worker(0).expect("another worker exists");
+        assert_eq!(picked.get().id, 1);
+    }
+
+    #[test]
+    fn on_worker_lost_purges_who_has_for_the_evicted_worker_only() {
+        let mut core = Core::default();
+        let lost = WorkerRef::wrap(test_worker(0, 0, false));
+        lost.get_mut().listen_address = "lost-worker".into();
+        let survivor = WorkerRef::wrap(test_worker(1, 0, false));
+        survivor.get_mut().listen_address = "survivor".into();
+        core.register_worker(lost.clone());
+        core.register_worker(survivor.clone());
+
+        core.who_has
+            .insert("key-a".to_string(), vec!["lost-worker".to_string()]);
+        core.who_has.insert(
+            "key-b".to_string(),
+            vec!["lost-worker".to_string(), "survivor".to_string()],
+        );
+
+        core.on_worker_lost(0);
+
+        assert!(core.who_has.get("key-a").is_none());
+        assert_eq!(
+            core.who_has.get("key-b").unwrap(),
+            &vec!["survivor".to_string()]
+        );
+        assert_eq!(core.workers().count(), 1);
+    }
+}
@@ -1,12 +1,12 @@
-use std::io::Bytes;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use futures::{FutureExt, SinkExt, StreamExt};
-use futures::future::Either;
-use tokio::codec::{Framed, LengthDelimitedCodec};
+use tokio::codec::Framed;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
+use crate::framing::{Frame, FramingCodec, MessageType};
 use crate::scheduler::{FromSchedulerMessage, SchedulerComm, ToSchedulerMessage};
 use crate::scheduler::schedproto::SchedulerRegistration;
 
@@ -15,25 +15,44 @@ pub struct RemoteScheduler;
 impl RemoteScheduler {
     pub async fn start(self, mut comm: SchedulerComm, address: &str) -> crate::Result<()> {
         let conn = TcpStream::connect(address).await?;
-        let conn = Framed::new(conn, LengthDelimitedCodec::new());
+        let conn = Framed::new(conn, FramingCodec::default());
         let (mut tx, mut rx) = conn.split();
 
+        // Every outgoing command gets a fresh request id so a reply can be
+        // correlated back to the request that caused it.
+        let next_request_id = AtomicU64::new(0);
+
         let SchedulerComm { mut recv, mut send } = comm;
         let receiver = async move {
             while let Some(msg) = recv.next().await {
+                let _span = crate::telemetry::start_span("scheduler_send", None);
                 let data = serde_json::to_vec(&msg)?;
                 log::debug!("Sending scheduler command: {:?}", msg);
-                tx.send(bytes::Bytes::from(data)).await?;
+                let request_id = next_request_id.fetch_add(1, Ordering::Relaxed);
+                tx.send(Frame {
+                    message_type: MessageType::Json,
+                    request_id,
+                    payload: data.into(),
+                })
+                    .await?;
             }
             Ok(())
         }
             .boxed_local();
 
         let sender = async move {
-            while let Some(msg) = rx.next().await {
-                let msg = msg?;
-                let data: FromSchedulerMessage = serde_json::from_slice(&msg)?;
-                log::debug!("Received scheduler command: {:?}", data);
+            while let Some(frame) = rx.next().await {
+                let frame = frame?;
+                let _span = crate::telemetry::start_span("scheduler_recv", None);
+                let data: FromSchedulerMessage = match frame.message_type {
+                    MessageType::Json => serde_json::from_slice(&frame.payload)?,
+                    MessageType::MessagePack => rmp_serde::from_read(frame.payload.as_ref())?,
+                };
+                log::debug!(
+                    "Received scheduler command (request_id={}): {:?}",
+                    frame.request_id,
+                    data
+                );
                 send.try_send(data).expect("Send failed");
             }
             Ok(())
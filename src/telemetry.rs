@@ -0,0 +1,119 @@
+//! Optional OpenTelemetry spans and metrics for the scheduler and worker
+//! paths, gated behind the `telemetry` feature so a build that doesn't want
+//! to talk to a collector pays nothing for it. Every item here is safe to
+//! call unconditionally from instrumented call sites (`compute_task`,
+//! `on_task_finished`, `send_tasks_to_workers`, the `RemoteScheduler`
+//! send/recv loops): with the feature off they compile away to no-ops.
+//!
+//! `Cargo.toml` needs a matching feature declaration for the feature gate
+//! above to do anything:
+//!
+//! ```toml
+//! [dependencies]
+//! opentelemetry = { version = "0.11", optional = true }
+//! once_cell = { version = "1", optional = true }
+//!
+//! [features]
+//! telemetry = ["opentelemetry", "once_cell"]
+//! ```
+//!
+//! The worker-side spans for a single task (`fetch_dependencies`,
+//! `compute_task`, `on_task_finished`) are tagged with a `task.id`
+//! attribute so a collector can group them; the scheduler-side spans
+//! (`send_tasks_to_workers`, `scheduler_send`, `scheduler_recv`) carry no
+//! task id, so they cannot be matched to a task by attribute. Spans are
+//! not linked into a single trace either: `run_job` and `report_finished`
+//! run on different threads/tasks
+//! (a pool thread and the reactor's collector task, respectively) with no
+//! parent context passed between them, so each shows up as its own
+//! trace root rather than as children of one `compute_task` span.
+
+#[cfg(feature = "telemetry")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "telemetry")]
+use opentelemetry::{
+    global,
+    metrics::{Counter, Meter, ValueRecorder},
+    trace::{Span, Tracer},
+    KeyValue,
+};
+
+#[cfg(feature = "telemetry")]
+static METER: Lazy<Meter> = Lazy::new(|| global::meter("rsds"));
+
+#[cfg(feature = "telemetry")]
+static TASKS_SCHEDULED: Lazy<Counter<u64>> =
+    Lazy::new(|| METER.u64_counter("rsds.tasks_scheduled").init());
+#[cfg(feature = "telemetry")]
+static TASKS_FINISHED: Lazy<Counter<u64>> =
+    Lazy::new(|| METER.u64_counter("rsds.tasks_finished").init());
+#[cfg(feature = "telemetry")]
+static BYTES_TRANSFERRED: Lazy<Counter<u64>> =
+    Lazy::new(|| METER.u64_counter("rsds.bytes_transferred").init());
+#[cfg(feature = "telemetry")]
+static TASK_COMPUTE_SECONDS: Lazy<ValueRecorder<f64>> =
+    Lazy::new(|| METER.f64_value_recorder("rsds.task_compute_seconds").init());
+#[cfg(feature = "telemetry")]
+static OUTSTANDING_TASKS: Lazy<ValueRecorder<u64>> =
+    Lazy::new(|| METER.u64_value_recorder("rsds.outstanding_tasks").init());
+
+/// A span following one step of a task's life (scheduling, computing,
+/// finishing). Spans started with a `task_id` carry it as the `task.id`
+/// attribute, which lets a collector group the spans of one task; spans
+/// started with `None` carry no task attribute. Ends when dropped.
+pub struct TaskSpan {
+    #[cfg(feature = "telemetry")]
+    span: opentelemetry::trace::BoxedSpan,
+}
+
+/// Starts a new span named `name`, tagged with `task_id` if given.
+pub fn start_span(name: &'static str, task_id: Option<String>) -> TaskSpan {
+    #[cfg(feature = "telemetry")]
+    {
+        let tracer = global::tracer("rsds");
+        let mut span = tracer.start(name);
+        if let Some(task_id) = task_id {
+            span.set_attribute(KeyValue::new("task.id", task_id));
+        }
+        TaskSpan { span }
+    }
+    #[cfg(not(feature = "telemetry"))]
+    {
+        let _ = (name, task_id);
+        TaskSpan {}
+    }
+}
+
+/// Records that a task was handed to a worker.
+pub fn record_task_scheduled() {
+    #[cfg(feature = "telemetry")]
+    TASKS_SCHEDULED.add(1, &[]);
+}
+
+/// Records that a task reached a terminal state, with its real compute
+/// duration and output size (as opposed to the synthetic placeholders the
+/// prototype execution path used to report).
+pub fn record_task_finished(compute_seconds: f64, nbytes: u64) {
+    #[cfg(feature = "telemetry")]
+    {
+        TASKS_FINISHED.add(1, &[]);
+        BYTES_TRANSFERRED.add(nbytes, &[]);
+        TASK_COMPUTE_SECONDS.record(compute_seconds, &[]);
+    }
+    #[cfg(not(feature = "telemetry"))]
+    {
+        let _ = (compute_seconds, nbytes);
+    }
+}
+
+/// Records how many tasks are currently outstanding (assigned but not yet
+/// finished) on a given worker, e.g. after the tranquilizer admits or holds
+/// back a batch.
+pub fn record_outstanding_tasks(worker_id: u64, count: u64) {
+    #[cfg(feature = "telemetry")]
+    OUTSTANDING_TASKS.record(count, &[KeyValue::new("worker.id", worker_id as i64)]);
+    #[cfg(not(feature = "telemetry"))]
+    {
+        let _ = (worker_id, count);
+    }
+}
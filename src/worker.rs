@@ -1,28 +1,102 @@
 use crate::common::WrappedRcRefCell;
-use crate::daskcodec::DaskCodec;
+use crate::framing::{Frame, FramingCodec, MessageType};
 use crate::messages::workermsg::{FromWorkerMessage, HeartbeatResponse, Status, ToWorkerMessage};
 use crate::prelude::*;
 use futures::future;
-use futures::future::FutureExt;
-use futures::sink::SinkExt;
+use futures::future::{Either, FutureExt};
+use futures::pin_mut;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 
 use crate::task::TaskRuntimeState;
 use rmp_serde as rmps;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::codec::Framed;
 use tokio::net::TcpStream;
 use tokio::runtime::current_thread;
+use tokio::time::delay_for;
 use crate::core::Core;
 use crate::messages::generic::RegisterWorkerMsg;
 
+/// How long we keep a draining worker connection open, waiting for its
+/// outstanding tasks to reach a terminal state, before closing it anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often we poll for drain completion while the recv/snd loops keep
+/// running underneath us.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Resolves once the process receives a shutdown request (SIGINT/SIGTERM,
+/// or Ctrl-C on non-unix platforms).
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let ctrl_c = tokio::signal::ctrl_c().fuse();
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    let term = sigterm.recv().fuse();
+    pin_mut!(ctrl_c);
+    pin_mut!(term);
+    future::select(ctrl_c, term).await;
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Number of recent task durations kept per worker to estimate its
+/// throughput (the "tranquilizer" sliding window).
+const TRANQUILIZER_WINDOW: usize = 20;
+
+/// Smoothing factor for the throughput EWMA; higher reacts faster to a
+/// worker speeding up or slowing down, lower is more stable under noise.
+const TRANQUILIZER_EWMA_ALPHA: f64 = 0.3;
+
+/// How many seconds of work we are willing to have in flight on a worker at
+/// once. The outstanding-task cap is `throughput (tasks/sec) * this`.
+const TRANQUILIZER_TARGET_LATENCY: Duration = Duration::from_millis(500);
+
+/// How often a worker is expected to check in (`KeepAlive`, or really any
+/// frame at all). Sent to workers as `HeartbeatResponse.heartbeat_interval`.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many missed heartbeat intervals we tolerate before treating a worker
+/// as dead, the same way we treat a closed TCP connection.
+const HEARTBEAT_MISS_LIMIT: u32 = 3;
 
 pub struct Worker {
     pub id: WorkerId,
     pub sender: tokio::sync::mpsc::UnboundedSender<Bytes>,
     pub ncpus: u32,
     pub listen_address: String,
+
+    /// Set once a shutdown signal has been received; `send_tasks_to_workers`
+    /// stops assigning new `ComputeTask`s to a draining worker.
+    pub draining: bool,
+
+    /// Number of `ComputeTask`s dispatched to this worker that have not yet
+    /// finished or erred. Used to know when it is safe to close a draining
+    /// connection, and to throttle dispatch below.
+    pub outstanding_tasks: usize,
+
+    /// Tasks that are ready to run on this worker but are being held back by
+    /// the tranquilizer because `outstanding_tasks` has already hit `task_cap()`.
+    pub pending_queue: VecDeque<TaskRef>,
+
+    /// Sliding window of the last few observed compute durations (seconds),
+    /// most recent at the back.
+    recent_durations: VecDeque<f64>,
+
+    /// Exponentially-weighted moving average of this worker's throughput, in
+    /// tasks/sec. Zero until the first task finishes.
+    throughput_ewma: f64,
+
+    /// Last time we received any frame at all from this worker. Scanned by
+    /// `monitor_worker_heartbeats` to detect silently-dead workers that never
+    /// cleanly close their TCP stream (e.g. a network partition).
+    pub last_seen: std::time::Instant,
 }
 
 impl Worker {
@@ -42,25 +116,80 @@ impl Worker {
         self.sender.try_send(data).unwrap(); // TODO: bail!("Send of worker XYZ failed")
         Ok(())
     }
+
+    /// How many simultaneously outstanding tasks we are willing to have on
+    /// this worker right now, based on its recent throughput. Before we have
+    /// any measurements we allow exactly one in flight, to probe latency
+    /// without risking overloading a worker we know nothing about.
+    fn task_cap(&self) -> usize {
+        if self.throughput_ewma <= 0.0 {
+            return 1;
+        }
+        let cap = self.throughput_ewma * TRANQUILIZER_TARGET_LATENCY.as_secs_f64();
+        (cap.ceil() as usize).max(1)
+    }
+
+    /// Common bookkeeping for a `TaskFinished`/`TaskErred` that just settled
+    /// a task this worker was running: frees the `outstanding_tasks` slot it
+    /// was holding (saturating, since a worker evicted by the heartbeat
+    /// monitor has already reset this to 0 before a late terminal message
+    /// from it trickles in) and lets the tranquilizer fill the freed slot
+    /// back up from `pending_queue`.
+    fn on_task_settled(&mut self, core: &Core) {
+        self.outstanding_tasks = self.outstanding_tasks.saturating_sub(1);
+        dispatch_ready(core, self);
+    }
+
+    /// Folds a freshly observed compute duration into `recent_durations` and,
+    /// from the window's mean (steadier than any single sample), into the
+    /// throughput EWMA.
+    fn record_duration(&mut self, duration_secs: f64) {
+        if duration_secs <= 0.0 {
+            return;
+        }
+        self.recent_durations.push_back(duration_secs);
+        if self.recent_durations.len() > TRANQUILIZER_WINDOW {
+            self.recent_durations.pop_front();
+        }
+        let mean_duration: f64 =
+            self.recent_durations.iter().sum::<f64>() / self.recent_durations.len() as f64;
+        let windowed_throughput = 1.0 / mean_duration;
+        self.throughput_ewma = if self.throughput_ewma <= 0.0 {
+            windowed_throughput
+        } else {
+            TRANQUILIZER_EWMA_ALPHA * windowed_throughput
+                + (1.0 - TRANQUILIZER_EWMA_ALPHA) * self.throughput_ewma
+        };
+    }
 }
 
 pub type WorkerRef = WrappedRcRefCell<Worker>;
 
+/// Drives one worker's connection: decodes `FramingCodec` frames off the
+/// socket and encodes outgoing ones the same way. The worker binary on the
+/// other end of this connection needs to speak `FramingCodec` too, not the
+/// older ad hoc codec this link used before it was unified onto the shared
+/// framing format.
 pub async fn start_worker(
     core_ref: &CoreRef,
     address: std::net::SocketAddr,
-    framed: Framed<TcpStream, DaskCodec>,
+    framed: Framed<TcpStream, FramingCodec>,
     msg: RegisterWorkerMsg,
 ) -> crate::Result<()> {
     let core_ref = core_ref.clone();
     let core_ref2 = core_ref.clone();
+    // `recv_loop` below is a `move` closure and takes ownership of whatever
+    // of `core_ref`/`worker_ref` it captures (both wrap an `Rc<RefCell<_>>`,
+    // not `Copy`), so the post-drain code after it runs needs its own clones
+    // rather than reusing the originals.
+    let core_ref3 = core_ref.clone();
 
     let (mut snd_sender, mut snd_receiver) = tokio::sync::mpsc::unbounded_channel::<Bytes>();
 
     let hb = HeartbeatResponse {
         status: "OK",
         time: 0.0,
-        heartbeat_interval: 1.0,
+        heartbeat_interval: HEARTBEAT_INTERVAL.as_secs_f64(),
         worker_plugins: Vec::new(),
     };
     let data = rmp_serde::encode::to_vec_named(&hb)?;
@@ -74,17 +203,48 @@ pub async fn start_worker(
             ncpus: 1, // TODO: real cpus
             sender: snd_sender,
             listen_address: msg.address,
+            draining: false,
+            outstanding_tasks: 0,
+            pending_queue: VecDeque::new(),
+            recent_durations: VecDeque::new(),
+            throughput_ewma: 0.0,
+            last_seen: std::time::Instant::now(),
         });
         core.register_worker(worker_ref.clone());
         (worker_id, worker_ref)
     };
+    let worker_ref2 = worker_ref.clone();
 
     log::info!("New worker registered as {} from {}", worker_id, address);
 
-    let (mut sender, receiver) = framed.split();
+    thread_local! {
+        static HEARTBEAT_MONITOR_STARTED: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    }
+    HEARTBEAT_MONITOR_STARTED.with(|started| {
+        if !started.get() {
+            started.set(true);
+            tokio::task::spawn_local(monitor_worker_heartbeats(core_ref.clone()));
+        }
+    });
+
+    // We only ever decode frames here (via `receiver`); writes go straight
+    // to the raw socket half through `write_frame_vectored` so a large
+    // ComputeTask/TaskFinished payload is handed to the OS alongside its
+    // header in one vectored write instead of first being copied into a
+    // fresh `BytesMut` the way going through `Framed`'s `Encoder` would.
+    let stream = framed.into_inner();
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let receiver = tokio::codec::FramedRead::new(read_half, FramingCodec::default());
+    let next_request_id = AtomicU64::new(0);
     let snd_loop = async move {
         while let Some(data) = snd_receiver.next().await {
-            if let Err(e) = sender.send(data).await {
+            let request_id = next_request_id.fetch_add(1, Ordering::Relaxed);
+            let frame = Frame {
+                message_type: MessageType::MessagePack,
+                request_id,
+                payload: data,
+            };
+            if let Err(e) = crate::framing::write_frame_vectored(&mut write_half, &frame).await {
                 log::error!("Send to worker failed");
                 return Err(e);
             }
@@ -93,23 +253,51 @@ pub async fn start_worker(
     }
         .boxed_local();
 
-    let recv_loop = receiver.try_for_each(move |data| {
-        let msgs: Result<Vec<FromWorkerMessage>, _> = rmps::from_read(std::io::Cursor::new(&data.message));
+    let recv_loop = receiver.try_for_each(move |frame| {
+        let msgs: Result<Vec<FromWorkerMessage>, _> = match frame.message_type {
+            MessageType::MessagePack => {
+                rmps::from_read(std::io::Cursor::new(&frame.payload))
+            }
+            MessageType::Json => serde_json::from_slice(&frame.payload)
+                .map_err(|e| rmp_serde::decode::Error::Syntax(e.to_string())),
+        };
         if let Err(e) = msgs {
-            dbg!(data);
+            dbg!(frame);
             panic!("Invalid message from worker ({}): {}", worker_id, e);
         }
+        worker_ref.get_mut().last_seen = std::time::Instant::now();
+
         let mut new_ready_scheduled = Vec::new();
         for msg in msgs.unwrap() {
             match msg {
                 FromWorkerMessage::TaskFinished(msg) => {
                     assert!(msg.status == Status::Ok); // TODO: handle other cases ??
+                    {
+                        let mut worker = worker_ref.get_mut();
+                        if let Some((_, start, end)) = msg.startstops.iter().find(|(label, _, _)| label == "compute") {
+                            worker.record_duration(end - start);
+                        }
+                        let core = core_ref.get();
+                        worker.on_task_settled(&core);
+                    }
                     let mut core = core_ref.get_mut();
                     core.on_task_finished(&worker_ref, msg, &mut new_ready_scheduled);
                 },
                 FromWorkerMessage::TaskErred(msg) => {
                     assert!(msg.status == Status::Error); // TODO: handle other cases ??
-                    
+                    {
+                        let mut worker = worker_ref.get_mut();
+                        let core = core_ref.get();
+                        worker.on_task_settled(&core);
+                    }
+                    // `on_task_erred` captures the exception and either
+                    // reschedules the task on a different worker (while its retry
+                    // count allows it) or, once retries are exhausted, moves it to
+                    // `Failed` and marks its dependents `Erred`; no message is sent
+                    // to the client from there. A retry puts the task back into
+                    // `new_ready_scheduled` just like a fresh schedule would.
+                    let mut core = core_ref.get_mut();
+                    core.on_task_erred(&worker_ref, msg, &mut new_ready_scheduled);
                 },
                 FromWorkerMessage::KeepAlive => { /* Do nothing by design */ }
             }
@@ -134,8 +322,76 @@ pub async fn start_worker(
         future::ready(Ok(()))
     });
 
-    let result = future::select(recv_loop, snd_loop).await;
-    if let Err(e) = result.factor_first().0 {
+    let shutdown_signal = wait_for_shutdown_signal().fuse();
+    let work = future::select(recv_loop, snd_loop);
+    pin_mut!(shutdown_signal);
+    pin_mut!(work);
+
+    let result = match future::select(work, shutdown_signal).await {
+        Either::Left((res, _)) => res.factor_first().0,
+        Either::Right((_, work)) => {
+            // Do not close the connection immediately on the shutdown signal:
+            // stop handing out new tasks to this worker and wait for whatever
+            // it is already computing (or whose TaskFinished/TaskErred is
+            // still in flight) to reach a terminal state, up to a timeout.
+            let stranded: Vec<TaskRef> = {
+                let mut worker = worker_ref2.get_mut();
+                worker.draining = true;
+                // These were never actually sent to this worker (they were
+                // just waiting for the tranquilizer to free up a slot), so
+                // unlike `outstanding_tasks` they are not "in flight" here;
+                // hand them back to Core instead of leaving them to be
+                // silently dropped once this connection closes.
+                worker.pending_queue.drain(..).collect()
+            };
+            if !stranded.is_empty() {
+                log::info!(
+                    "Worker {} draining: rescheduling {} pending task(s) that were never dispatched",
+                    worker_id,
+                    stranded.len()
+                );
+                let mut core = core_ref3.get_mut();
+                for task_ref in stranded {
+                    core.reschedule_task(task_ref);
+                }
+            }
+            log::info!(
+                "Shutdown requested, worker {} draining {} outstanding task(s)",
+                worker_id,
+                worker_ref2.get().outstanding_tasks
+            );
+
+            let drained = async {
+                while worker_ref2.get().outstanding_tasks > 0 {
+                    // `work` keeps polling the recv/snd loops concurrently
+                    // (driven by the outer select below), so TaskFinished and
+                    // TaskErred messages keep decrementing `outstanding_tasks`
+                    // while we wait here.
+                    delay_for(DRAIN_POLL_INTERVAL).await;
+                }
+            };
+            pin_mut!(drained);
+            let timeout = delay_for(DRAIN_TIMEOUT).fuse();
+            pin_mut!(timeout);
+
+            match future::select(work, future::select(drained, timeout)).await {
+                Either::Left((res, _)) => res.factor_first().0,
+                Either::Right((Either::Left(_), _work)) => {
+                    log::info!("Worker {} drained cleanly, closing", worker_id);
+                    Ok(())
+                }
+                Either::Right((Either::Right(_), _work)) => {
+                    log::warn!(
+                        "Worker {} drain timeout elapsed with {} outstanding task(s), closing anyway",
+                        worker_id,
+                        worker_ref2.get().outstanding_tasks
+                    );
+                    Ok(())
+                }
+            }
+        }
+    };
+    if let Err(e) = result {
         log::error!(
             "Error in worker connection (id={}, connection={}): {}",
             worker_id,
@@ -148,22 +404,194 @@ pub async fn start_worker(
         worker_id,
         address
     );
-    let mut core = core_ref2.get_mut();
-    core.unregister_worker(worker_id);
+    // Whatever this worker was still holding (Assigned/Computing tasks that
+    // never got a terminal TaskFinished/TaskErred, e.g. a hard IO error or a
+    // drain that timed out with `outstanding_tasks > 0`) needs to be
+    // rescheduled, not just forgotten — the same cleanup
+    // `monitor_worker_heartbeats` runs for a worker it times out.
+    core_ref2.get_mut().on_worker_lost(worker_id);
     Ok(())
 }
 
 
 pub fn send_tasks_to_workers(core: &Core, tasks_per_worker: HashMap<WorkerRef, Vec<TaskRef>>) {
     for (worker_ref, tasks) in tasks_per_worker {
-        let msgs: Vec<_> = tasks.iter().map(|t| ToWorkerMessage::ComputeTask(t.get().make_compute_task_msg(core))).collect();
-        let data = rmp_serde::encode::to_vec_named(&msgs).unwrap();
         let mut worker = worker_ref.get_mut();
-        worker.send_message(data.into()).unwrap_or_else(|_| {
-            // !!! Do not propagate error right now, we need to finish sending messages to others
-            // Worker cleanup is done elsewhere (when worker future terminates),
-            // so we can safely ignore this. Since we are nice guys we log (debug) message.
-            log::debug!("Sending tasks to worker {} failed", worker.id);
-        });
+        if worker.draining {
+            // Worker is shutting down; do not hand out new work to it.
+            log::debug!(
+                "Worker {} is draining, withholding {} task(s) from dispatch",
+                worker.id,
+                tasks.len()
+            );
+            continue;
+        }
+        worker.pending_queue.extend(tasks);
+        dispatch_ready(core, &mut worker);
+    }
+}
+
+/// Sends as many tasks from `worker`'s pending queue as its tranquilizer cap
+/// currently allows, leaving the rest queued until a `TaskFinished`/`TaskErred`
+/// frees up a slot.
+fn dispatch_ready(core: &Core, worker: &mut Worker) {
+    if worker.draining {
+        // A draining worker's pending queue is drained (and its tasks
+        // rescheduled) once, right when draining starts; nothing should be
+        // handed out to it afterwards, including anything a caller might
+        // still have queued here.
+        return;
+    }
+    let _span = crate::telemetry::start_span("send_tasks_to_workers", None);
+    let cap = worker.task_cap();
+    let mut batch = Vec::new();
+    while worker.outstanding_tasks + batch.len() < cap {
+        match worker.pending_queue.pop_front() {
+            Some(task_ref) => batch.push(task_ref),
+            None => break,
+        }
+    }
+    if batch.is_empty() {
+        return;
+    }
+    log::debug!(
+        "Dispatching {} task(s) to worker {} (cap={}, outstanding={}, still queued={})",
+        batch.len(),
+        worker.id,
+        cap,
+        worker.outstanding_tasks,
+        worker.pending_queue.len()
+    );
+    let msgs: Vec<_> = batch
+        .iter()
+        .map(|t| ToWorkerMessage::ComputeTask(t.get().make_compute_task_msg(core)))
+        .collect();
+    let data = rmp_serde::encode::to_vec_named(&msgs).unwrap();
+    worker.outstanding_tasks += batch.len();
+    for _ in &batch {
+        crate::telemetry::record_task_scheduled();
+    }
+    crate::telemetry::record_outstanding_tasks(worker.id as u64, worker.outstanding_tasks as u64);
+    worker.send_message(data.into()).unwrap_or_else(|_| {
+        // !!! Do not propagate error right now, we need to finish sending messages to others
+        // Worker cleanup is done elsewhere (when worker future terminates),
+        // so we can safely ignore this. Since we are nice guys we log (debug) message.
+        log::debug!("Sending tasks to worker {} failed", worker.id);
+    });
+}
+
+/// Runs for the lifetime of the scheduler, periodically scanning registered
+/// workers for ones that have not sent a single frame (heartbeat or
+/// otherwise) in `HEARTBEAT_MISS_LIMIT` heartbeat intervals. A timed-out
+/// worker is treated exactly like one whose TCP connection closed: it is
+/// unregistered, its Assigned/Computing tasks are rescheduled onto live
+/// workers, and it is purged from `who_has` bookkeeping. This is what
+/// catches a worker lost to a network partition, which never gets the
+/// chance to close its stream cleanly.
+///
+/// Started once, the first time any worker connects (see `start_worker`);
+/// a single instance covers every worker registered with `core_ref` for the
+/// rest of the process's life.
+pub async fn monitor_worker_heartbeats(core_ref: CoreRef) {
+    let timeout = HEARTBEAT_INTERVAL * HEARTBEAT_MISS_LIMIT;
+    let mut tick = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        tick.tick().await;
+        let timed_out: Vec<WorkerRef> = {
+            let core = core_ref.get();
+            core.workers()
+                .filter(|w| w.get().last_seen.elapsed() > timeout)
+                .cloned()
+                .collect()
+        };
+        for worker_ref in timed_out {
+            // Every task this worker was holding is about to be reassigned,
+            // so its bookkeeping is reset here rather than left to whatever
+            // TaskFinished/TaskErred messages (if any) still trickle in from
+            // it afterwards.
+            let worker_id = {
+                let mut worker = worker_ref.get_mut();
+                worker.outstanding_tasks = 0;
+                worker.pending_queue.clear();
+                worker.id
+            };
+            log::warn!(
+                "Worker {} has not been heard from in over {:?}, treating as dead",
+                worker_id,
+                timeout
+            );
+            core_ref.get_mut().on_worker_lost(worker_id);
+        }
+    }
+}
+
+/// Builds a bare `Worker` for tests, in this module and in
+/// [`crate::core`]'s. `recent_durations`/`throughput_ewma` aren't exposed
+/// here since they're private to this module; callers that need them
+/// (this module's own `tests`) set them up afterwards.
+#[cfg(test)]
+pub(crate) fn test_worker(id: WorkerId, outstanding_tasks: usize, draining: bool) -> Worker {
+    let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel::<Bytes>();
+    Worker {
+        id,
+        sender,
+        ncpus: 1,
+        listen_address: String::new(),
+        draining,
+        outstanding_tasks,
+        pending_queue: VecDeque::new(),
+        recent_durations: VecDeque::new(),
+        throughput_ewma: 0.0,
+        last_seen: std::time::Instant::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_worker() -> Worker {
+        super::test_worker(0, 0, false)
+    }
+
+    #[test]
+    fn task_cap_allows_exactly_one_before_any_measurement() {
+        let worker = test_worker();
+        assert_eq!(worker.task_cap(), 1);
+    }
+
+    #[test]
+    fn task_cap_grows_with_measured_throughput() {
+        let mut worker = test_worker();
+        // 10 tasks/sec sustained => cap should be throughput * target latency,
+        // i.e. 10 * 0.5s = 5.
+        for _ in 0..TRANQUILIZER_WINDOW {
+            worker.record_duration(0.1);
+        }
+        assert_eq!(worker.task_cap(), 5);
+    }
+
+    #[test]
+    fn record_duration_keeps_only_the_last_tranquilizer_window_durations() {
+        let mut worker = test_worker();
+        for _ in 0..(TRANQUILIZER_WINDOW + 5) {
+            worker.record_duration(0.1);
+        }
+        assert_eq!(worker.recent_durations.len(), TRANQUILIZER_WINDOW);
+    }
+
+    #[test]
+    fn on_task_settled_frees_a_slot_without_underflowing_past_zero() {
+        let core = Core::default();
+        let mut worker = test_worker();
+        worker.outstanding_tasks = 1;
+
+        worker.on_task_settled(&core);
+        assert_eq!(worker.outstanding_tasks, 0);
+
+        // A worker already reset to 0 by the heartbeat monitor must not
+        // underflow when a late TaskFinished/TaskErred still arrives for it.
+        worker.on_task_settled(&core);
+        assert_eq!(worker.outstanding_tasks, 0);
     }
 }
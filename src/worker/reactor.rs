@@ -1,45 +1,438 @@
+use crate::framing::{Frame, FramingCodec, MessageType};
 use crate::protocol::protocol::{serialize_single_packet, SerializedTransport};
 use crate::protocol::workermsg::{
     AddKeysMsg, ComputeTaskMsg, FromWorkerMessage, Status, TaskFinishedMsg,
 };
 use crate::worker::state::WorkerStateRef;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, SystemTime};
+use tokio::codec::Framed;
+use tokio::net::{TcpListener, TcpStream as TokioTcpStream};
 
-pub fn compute_task(state_ref: &WorkerStateRef, mut msg: ComputeTaskMsg) -> crate::Result<()> {
-    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap();
-    let mut state = state_ref.get_mut();
+/// Key type as seen on the wire; kept as a local alias rather than pulling in
+/// the scheduler's key type so this module stays decoupled from it.
+type TaskKey = String;
+
+/// One unit of work handed to a job-runner thread.
+///
+/// Only dependencies this worker already had in its local store are
+/// resolved into `local_inputs` up front (a cheap in-memory lookup, done on
+/// the reactor thread in [`compute_task`]). Anything still missing is left
+/// in `missing` and fetched from a peer by [`run_job`] on the pool thread
+/// instead, so the blocking network round-trip never happens while the
+/// single-threaded reactor is driving anything else.
+struct Job {
+    msg: ComputeTaskMsg,
+    local_inputs: HashMap<TaskKey, Vec<u8>>,
+    missing: Vec<(TaskKey, Vec<String>)>,
+}
+
+/// Outcome of running a `Job` on a pool thread, ready to be turned into a
+/// `TaskFinishedMsg`.
+struct JobOutput {
+    key: TaskKey,
+    status: Status,
+    data: Vec<u8>,
+    type_name: String,
+    /// Wall-clock bounds of fetching missing dependencies from peers,
+    /// `None` if every dependency was already local. Reported as its own
+    /// `"transfer"` startstop, kept separate from `compute` so remote-fetch
+    /// latency doesn't get folded into the duration the tranquilizer uses
+    /// to estimate this worker's throughput.
+    transfer: Option<(SystemTime, SystemTime)>,
+    /// Wall-clock bounds of the `run_task` call itself.
+    compute: Option<(SystemTime, SystemTime)>,
+    /// Dependencies this job had to fetch from a peer while it ran. Folded
+    /// into the local store by `report_finished` (back on the reactor
+    /// thread, the only place allowed to touch `WorkerState`).
+    fetched: Vec<(TaskKey, Vec<u8>)>,
+}
+
+/// A small fixed-size pool of OS threads that actually execute tasks. Sized
+/// to the worker's `ncpus` so we never run more concurrent computations than
+/// there are cores.
+struct JobPool {
+    /// Unbounded; sending here never blocks. Backpressure against the
+    /// bounded job queue shared by the runner threads is absorbed by the feeder thread
+    /// spawned in `new`, not by whoever calls `submit` (the single-threaded
+    /// async reactor, which must never block).
+    intake: std::sync::mpsc::Sender<Job>,
+}
 
-    let fetched_keys: Vec<_> = std::mem::take(&mut msg.who_has)
-        .into_iter()
-        .map(|(k, _)| k)
-        .filter(|k| !state.local_keys.contains(k))
-        .collect();
+impl JobPool {
+    fn new(ncpus: u32, results: SyncSender<JobOutput>) -> Self {
+        let ncpus = ncpus.max(1) as usize;
+        let (jobs, job_receiver) = sync_channel::<Job>(ncpus);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
 
-    if !fetched_keys.is_empty() {
-        for key in &fetched_keys {
-            assert!(state.local_keys.insert(key.clone()));
+        for _ in 0..ncpus {
+            let job_receiver = job_receiver.clone();
+            let results = results.clone();
+            thread::spawn(move || loop {
+                let job = {
+                    let receiver = job_receiver.lock().unwrap();
+                    receiver.recv()
+                };
+                match job {
+                    Ok(job) => {
+                        if results.send(run_job(job)).is_err() {
+                            break; // receiving end gone, worker is shutting down
+                        }
+                    }
+                    Err(_) => break, // pool shut down, no more jobs will arrive
+                }
+            });
         }
-        state.send(serialize_single_packet(FromWorkerMessage::<
+
+        let (intake, intake_rx) = std::sync::mpsc::channel::<Job>();
+        thread::spawn(move || {
+            // Feeds `jobs` (bounded) from `intake` (unbounded), blocking here
+            // rather than on the caller when every runner thread is busy.
+            while let Ok(job) = intake_rx.recv() {
+                if jobs.send(job).is_err() {
+                    break;
+                }
+            }
+        });
+
+        JobPool { intake }
+    }
+
+    /// Queues a job for execution. Never blocks.
+    fn submit(&self, job: Job) {
+        let _ = self.intake.send(job);
+    }
+}
+
+/// Runs a single task to completion on the calling (pool) thread, fetching
+/// any still-missing dependencies from peers first. Safe to block here: this
+/// is an OS thread dedicated to computation, not the reactor.
+fn run_job(job: Job) -> JobOutput {
+    let Job {
+        msg,
+        mut local_inputs,
+        missing,
+    } = job;
+
+    let mut fetched = Vec::with_capacity(missing.len());
+    let transfer = if missing.is_empty() {
+        None
+    } else {
+        let _span = crate::telemetry::start_span("fetch_dependencies", Some(msg.key.clone()));
+        let transfer_start = SystemTime::now();
+        for (key, peers) in missing {
+            match fetch_from_peers(&key, &peers) {
+                Ok(data) => {
+                    local_inputs.insert(key.clone(), data.clone());
+                    fetched.push((key, data));
+                }
+                Err(e) => {
+                    log::warn!("Task {} failed to fetch dependency {}: {}", msg.key, key, e);
+                    return JobOutput {
+                        key: msg.key,
+                        status: Status::Error,
+                        type_name: String::new(),
+                        data: e.to_string().into_bytes(),
+                        transfer: Some((transfer_start, SystemTime::now())),
+                        compute: None,
+                        fetched,
+                    };
+                }
+            }
+        }
+        Some((transfer_start, SystemTime::now()))
+    };
+
+    let _span = crate::telemetry::start_span("compute_task", Some(msg.key.clone()));
+    let compute_start = SystemTime::now();
+    let result = crate::task_runtime::run_task(&msg.function, &msg.args, &local_inputs);
+    let compute_end = SystemTime::now();
+    match result {
+        Ok(output) => JobOutput {
+            key: msg.key,
+            status: Status::Ok,
+            type_name: output.type_name,
+            data: output.data,
+            transfer,
+            compute: Some((compute_start, compute_end)),
+            fetched,
+        },
+        Err(e) => {
+            log::warn!("Task {} failed: {}", msg.key, e);
+            JobOutput {
+                key: msg.key,
+                status: Status::Error,
+                type_name: String::new(),
+                data: e.to_string().into_bytes(),
+                transfer,
+                compute: Some((compute_start, compute_end)),
+                fetched,
+            }
+        }
+    }
+}
+
+thread_local! {
+    static JOB_POOL: RefCell<Option<JobPool>> = RefCell::new(None);
+}
+
+/// Lazily starts the job pool and its result-collector task the first time a
+/// task is computed, then submits `job` to it.
+fn submit_job(state_ref: &WorkerStateRef, job: Job) {
+    JOB_POOL.with(|cell| {
+        let mut pool = cell.borrow_mut();
+        if pool.is_none() {
+            let ncpus = state_ref.get().ncpus;
+            let (results_tx, results_rx) = sync_channel::<JobOutput>(ncpus.max(1) as usize * 2);
+            *pool = Some(JobPool::new(ncpus, results_tx));
+
+            let state_ref = state_ref.clone();
+            tokio::task::spawn_local(async move {
+                // `sync_channel`'s receiver is blocking, so we hop it onto a
+                // dedicated OS thread and forward completions into the
+                // reactor through a tokio channel the collector can `.await`.
+                let (forward_tx, mut forward_rx) = tokio::sync::mpsc::unbounded_channel();
+                thread::spawn(move || {
+                    while let Ok(output) = results_rx.recv() {
+                        if forward_tx.send(output).is_err() {
+                            break;
+                        }
+                    }
+                });
+                while let Some(output) = forward_rx.recv().await {
+                    report_finished(&state_ref, output);
+                }
+            });
+        }
+        pool.as_ref().unwrap().submit(job);
+    });
+}
+
+fn report_finished(state_ref: &WorkerStateRef, output: JobOutput) {
+    let _span = crate::telemetry::start_span("on_task_finished", Some(output.key.clone()));
+    let mut state = state_ref.get_mut();
+
+    if !output.fetched.is_empty() {
+        let mut fetched_keys = Vec::with_capacity(output.fetched.len());
+        for (key, data) in output.fetched {
+            state.local_keys.insert(key.clone());
+            state.store.insert(key.clone(), data);
+            fetched_keys.push(key);
+        }
+        let send_result = state.send(serialize_single_packet(FromWorkerMessage::<
             SerializedTransport,
         >::AddKeys(AddKeysMsg {
             keys: fetched_keys,
-        }))?);
+        })));
+        if let Err(e) = send_result {
+            log::error!("Failed to report fetched dependencies: {}", e);
+        }
+    }
+
+    state.local_keys.insert(output.key.clone());
+    let nbytes = output.data.len() as u64;
+    let _ = state.store.insert(output.key.clone(), output.data);
+
+    let mut startstops = Vec::with_capacity(2);
+    if let Some((start, end)) = output.transfer {
+        let (start, end) = as_secs_f64_pair(start, end);
+        startstops.push(("transfer".into(), start, end));
+    }
+    let mut compute_secs = 0.0;
+    if let Some((start, end)) = output.compute {
+        let (start, end) = as_secs_f64_pair(start, end);
+        compute_secs = end - start;
+        startstops.push(("compute".into(), start, end));
     }
-    state.local_keys.insert(msg.key.clone());
-    state.send(serialize_single_packet(FromWorkerMessage::<
+    crate::telemetry::record_task_finished(compute_secs, nbytes);
+    let send_result = state.send(serialize_single_packet(FromWorkerMessage::<
         SerializedTransport,
     >::TaskFinished(
         TaskFinishedMsg {
-            status: Status::Ok,
-            key: msg.key,
-            nbytes: 20,
-            r#type: vec![],
-            startstops: vec![(
-                "compute".into(),
-                now.as_secs_f64(),
-                (now + Duration::from_micros(10)).as_secs_f64(),
-            )],
+            status: output.status,
+            key: output.key,
+            nbytes,
+            r#type: output.type_name.into_bytes(),
+            startstops,
+        },
+    )));
+    if let Err(e) = send_result {
+        log::error!("Failed to report finished task: {}", e);
+    }
+}
+
+/// Converts a `(start, end)` pair of `SystemTime`s into seconds-since-epoch,
+/// the form `startstops` reports times in on the wire.
+fn as_secs_f64_pair(start: SystemTime, end: SystemTime) -> (f64, f64) {
+    let start = start
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let end = end
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    (start.as_secs_f64(), end.as_secs_f64())
+}
+
+/// Request body for fetching a key from a peer worker's store. MessagePack
+/// on the wire, same as every other payload on the worker link.
+#[derive(Serialize, Deserialize)]
+struct GetDataMsg {
+    key: TaskKey,
+}
+
+/// Response to a [`GetDataMsg`]. The wire protocol has no other way to tell
+/// "the peer doesn't hold this key" apart from "the peer holds an empty
+/// value", so `found` carries that distinction explicitly instead of
+/// overloading an empty payload for it.
+#[derive(Serialize, Deserialize)]
+struct GetDataResponse {
+    found: bool,
+    data: Vec<u8>,
+}
+
+/// Fetches a single key's bytes from one of its known holders, trying each
+/// peer address in turn until one actually holds the key. Speaks the same
+/// [`crate::framing`] header every other link on this worker uses, with a
+/// [`GetDataMsg`] request and a [`GetDataResponse`] as the MessagePack
+/// payload; [`serve_peer_fetches`] is the matching server side run by peers.
+fn fetch_from_peers(key: &TaskKey, peers: &[String]) -> crate::Result<Vec<u8>> {
+    let mut last_err = None;
+    for peer in peers {
+        match fetch_from_peer(key, peer) {
+            Ok(data) => return Ok(data),
+            Err(e) => {
+                log::debug!("Failed to fetch key {} from peer {}: {}", key, peer, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| crate::Error::from(format!("No peers hold key {}", key))))
+}
+
+fn fetch_from_peer(key: &TaskKey, peer_address: &str) -> crate::Result<Vec<u8>> {
+    use std::io::{Read, Write};
+    use tokio::codec::{Decoder, Encoder};
+
+    let mut stream = TcpStream::connect(peer_address)?;
+    let mut codec = FramingCodec::default();
+
+    let request = Frame {
+        message_type: MessageType::MessagePack,
+        request_id: 0,
+        payload: rmp_serde::to_vec(&GetDataMsg { key: key.clone() })?.into(),
+    };
+    let mut out = bytes::BytesMut::new();
+    codec.encode(request, &mut out)?;
+    stream.write_all(&out)?;
+
+    let mut buf = bytes::BytesMut::new();
+    loop {
+        if let Some(frame) = codec.decode(&mut buf)? {
+            let response: GetDataResponse = rmp_serde::from_read(frame.payload.as_ref())?;
+            return if response.found {
+                Ok(response.data)
+            } else {
+                Err(crate::Error::from(format!(
+                    "Peer {} does not hold key {}",
+                    peer_address, key
+                )))
+            };
+        }
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(crate::Error::from(format!(
+                "Peer {} closed connection while fetching key {}",
+                peer_address, key
+            )));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Serves other workers' [`fetch_from_peer`] requests against our local
+/// key/value store. Runs for the lifetime of the worker process, accepting
+/// one connection per requester and answering each `GetDataMsg` with a
+/// [`GetDataResponse`] carrying the matching bytes, or `found: false` if we
+/// no longer hold the key.
+pub async fn serve_peer_fetches(
+    state_ref: WorkerStateRef,
+    mut listener: TcpListener,
+) -> crate::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let state_ref = state_ref.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = handle_peer_fetch(&state_ref, stream).await {
+                log::debug!("Peer fetch connection from {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_peer_fetch(
+    state_ref: &WorkerStateRef,
+    stream: TokioTcpStream,
+) -> crate::Result<()> {
+    let mut framed = Framed::new(stream, FramingCodec::default());
+    while let Some(frame) = framed.next().await {
+        let frame = frame?;
+        let request: GetDataMsg = rmp_serde::from_read(frame.payload.as_ref())?;
+        let response = match state_ref.get().store.get(&request.key) {
+            Some(data) => GetDataResponse {
+                found: true,
+                data: data.clone(),
+            },
+            None => GetDataResponse {
+                found: false,
+                data: Vec::new(),
+            },
+        };
+        let payload = rmp_serde::to_vec(&response)?;
+        framed
+            .send(Frame {
+                message_type: MessageType::MessagePack,
+                request_id: frame.request_id,
+                payload: payload.into(),
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+pub fn compute_task(state_ref: &WorkerStateRef, mut msg: ComputeTaskMsg) -> crate::Result<()> {
+    let dependencies = std::mem::take(&mut msg.who_has);
+    let mut local_inputs = HashMap::with_capacity(dependencies.len());
+    let mut missing = Vec::new();
+    {
+        // Only an in-memory lookup happens under this borrow; any actual
+        // fetching (and thus blocking network IO) is done by `run_job` on a
+        // pool thread, after this borrow has long since been dropped.
+        let state = state_ref.get();
+        for (key, peers) in dependencies {
+            match state.store.get(&key) {
+                Some(data) => {
+                    local_inputs.insert(key, data.clone());
+                }
+                None => missing.push((key, peers)),
+            }
+        }
+    }
+
+    submit_job(
+        state_ref,
+        Job {
+            msg,
+            local_inputs,
+            missing,
         },
-    ))?);
+    );
     Ok(())
 }